@@ -7,12 +7,19 @@ use near_sdk::{
     assert_one_yocto, bs58, env, ext_contract, near_bindgen, require, AccountId, Balance,
     BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseError, PromiseOrValue, ONE_YOCTO,
 };
-use std::ops::{Mul, Sub};
-use std::time::SystemTime;
+use std::ops::Mul;
 
 pub mod external;
 pub use crate::external::*;
 
+mod clock;
+mod settlement;
+mod store;
+
+use clock::{Clock, NearClock};
+use settlement::{resolve_claim_payout, settle_claim, settle_revert, ClaimPayoutOutcome};
+use store::RoomStore;
+
 pub const TGAS: u64 = 1_000_000_000_000;
 pub const FT_TRANSFER_GAS: Gas = Gas(10_000_000_000_000);
 pub const WITHDRAW_CALLBACK_GAS: Gas = Gas(10_000_000_000_000);
@@ -61,6 +68,15 @@ pub struct Room {
     pending_amount: u128,
     claimed: bool,
     reverted: bool,
+    // Set by `settle_claim` when `claimed` is set; `resolve_claim_callback`
+    // flips the matching `*_paid` flag once its `ft_transfer` actually lands,
+    // so a partial failure (one leg pays, the other doesn't) can be retried
+    // for just the unpaid leg instead of resending both and double-paying
+    // whichever one already succeeded.
+    advisor_amount: Balance,
+    refund_amount: Balance,
+    advisor_paid: bool,
+    refund_paid: bool,
 }
 
 #[near_bindgen]
@@ -71,11 +87,28 @@ pub struct Contract {
     pub token_address: AccountId,
     pub verified_amount: Balance,
     pub room_list: LookupMap<u128, Room>,
+    pub is_paused: bool,
+    pub nonce_list: LookupMap<AccountId, u64>,
+    pub treasury_address: AccountId,
+    pub fee_basis_points: u16,
+    pub collected_fees: Balance,
+    pub public_key_list: LookupMap<AccountId, Vec<u8>>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractOld {
+    pub owner: AccountId,
+    pub staking_address: AccountId,
+    pub token_address: AccountId,
+    pub verified_amount: Balance,
+    pub room_list: LookupMap<u128, Room>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, BorshStorageKey)]
 pub enum StorageKey {
     RoomIDKey,
+    NonceKey,
+    PublicKeyKey,
 }
 
 #[near_bindgen]
@@ -85,6 +118,7 @@ impl Contract {
         _verified_amount: U128,
         _token_address: AccountId,
         _staking_address: AccountId,
+        _treasury_address: AccountId,
     ) -> Self {
         Contract {
             owner: env::signer_account_id(),
@@ -92,6 +126,98 @@ impl Contract {
             token_address: _token_address,
             verified_amount: u128::from(_verified_amount),
             room_list: LookupMap::new(StorageKey::RoomIDKey),
+            is_paused: false,
+            nonce_list: LookupMap::new(StorageKey::NonceKey),
+            treasury_address: _treasury_address,
+            fee_basis_points: 500,
+            collected_fees: 0,
+            public_key_list: LookupMap::new(StorageKey::PublicKeyKey),
+        }
+    }
+
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: ContractOld = env::state_read().expect("App: failed to read old state");
+        Contract {
+            owner: old.owner.clone(),
+            staking_address: old.staking_address,
+            token_address: old.token_address,
+            verified_amount: old.verified_amount,
+            room_list: old.room_list,
+            is_paused: false,
+            nonce_list: LookupMap::new(StorageKey::NonceKey),
+            treasury_address: old.owner,
+            fee_basis_points: 500,
+            collected_fees: 0,
+            public_key_list: LookupMap::new(StorageKey::PublicKeyKey),
+        }
+    }
+
+    // Lets an advisor or the admin register the ed25519 public key that
+    // `verify()` will trust for their account, so a signature can no longer
+    // be checked against whatever key the *caller* hands in at call time.
+    pub fn register_public_key(&mut self, _public_key: Vec<u8>) {
+        self.public_key_list
+            .insert(&env::predecessor_account_id(), &_public_key);
+    }
+
+    #[private]
+    pub fn assert_not_paused(&self) {
+        require!(!self.is_paused, "App: contract is paused");
+    }
+
+    pub fn pause_contract(&mut self) {
+        require!(
+            env::predecessor_account_id() == self.owner,
+            "App: Only owner can pause the contract!"
+        );
+        self.is_paused = true;
+    }
+
+    pub fn resume_contract(&mut self) {
+        require!(
+            env::predecessor_account_id() == self.owner,
+            "App: Only owner can resume the contract!"
+        );
+        self.is_paused = false;
+    }
+
+    pub fn set_fee(&mut self, _fee_basis_points: u16) {
+        require!(
+            env::predecessor_account_id() == self.owner,
+            "App: Only owner can set the fee!"
+        );
+        require!(_fee_basis_points <= 2000, "App: Fee too high!");
+        self.fee_basis_points = _fee_basis_points;
+    }
+
+    pub fn withdraw_fees(&mut self) {
+        require!(
+            env::predecessor_account_id() == self.owner,
+            "App: Only owner can withdraw fees!"
+        );
+        let amount = self.collected_fees;
+        require!(amount > 0, "App: No fees to withdraw!");
+        self.collected_fees = 0;
+
+        ext_ft_contract::ext(self.token_address.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_transfer(self.treasury_address.clone(), U128::from(amount), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(5 * TGAS))
+                    .resolve_withdraw_fees_callback(U128::from(amount)),
+            );
+    }
+
+    #[private]
+    pub fn resolve_withdraw_fees_callback(
+        &mut self,
+        amount: U128,
+        #[callback_result] res: Result<(), PromiseError>,
+    ) {
+        if res.is_err() {
+            self.collected_fees += u128::from(amount);
         }
     }
 
@@ -106,19 +232,22 @@ impl Contract {
         _room_id: Option<U128>,
         _minutes_lasts: Option<i64>,
         _signature: Option<Vec<u8>>,
-        _signer: Option<Vec<u8>>,
+        _nonce: Option<u64>,
     ) -> PromiseOrValue<U128> {
+        self.assert_not_paused();
         let _amount_per_minute = u128::from(_amount_per_minute.unwrap());
         let _room_id = u128::from(_room_id.unwrap());
-        // require!(
-        //     Self::verify(
-        //         &self,
-        //         _signature.unwrap(),
-        //         _signer.unwrap(),
-        //         _advisor.clone().unwrap()
-        //     ) == true,
-        //     "There was an error verifying advisor's signature"
-        // );
+        require!(
+            Self::verify(
+                self,
+                _signature.unwrap(),
+                _advisor.clone().unwrap(),
+                "ft_on_transfer",
+                _room_id,
+                _nonce.unwrap()
+            ) == true,
+            "There was an error verifying advisor's signature"
+        );
 
         if msg == "create_room" {
             Self::query_staked_amount(&self, _advisor.clone().unwrap());
@@ -134,6 +263,10 @@ impl Contract {
                 pending_amount: _pending_amount,
                 claimed: false,
                 reverted: false,
+                advisor_amount: 0,
+                refund_amount: 0,
+                advisor_paid: false,
+                refund_paid: false,
             };
 
             self.room_list.insert(&_room_id, &room);
@@ -144,6 +277,10 @@ impl Contract {
                 "App: Room not existed!"
             );
             require!(sender_id == room.learner, "App: Invalid learner!");
+            require!(
+                _amount_per_minute == room.amount_per_minute,
+                "App: Cannot extend a room at a different rate!"
+            );
             room.minutes_last += _minutes_lasts.unwrap();
             room.pending_amount += _amount_per_minute.mul(_minutes_lasts.unwrap() as u128);
 
@@ -160,78 +297,235 @@ impl Contract {
         _room_id: U128,
         _learner_vote: u8,
         _signature: Vec<u8>,
-        _signer: Vec<u8>,
+        _nonce: u64,
     ) {
         assert_one_yocto();
+        self.assert_not_paused();
         let _room_id = u128::from(_room_id);
         require!(
-            self.room_list.contains_key(&_room_id) == true,
+            self.contains_room(_room_id) == true,
             "App: Room not existed!"
         );
-        let mut room = self.room_list.get(&_room_id).unwrap();
-        // require!(
-        //     Self::verify(&self, _signature, _signer, room.advisor.clone()) == true,
-        //     "There was an error verifying advisor's signature"
-        // );
-
-        require!(room.claimed == false, "App: Already claimed!");
-        require!(room.reverted == false, "App: Already reverted!");
-
-        // let minutes_last = Utc::now().timestamp().sub(room.start_time);
-        // require!(
-        //     minutes_last >= room.minutes_last,
-        //     "App: Too early to reveive token!"
-        // );
-
-        ext_stake_contract::ext(self.token_address.clone())
+        let room = self.get_room(_room_id).unwrap();
+        require!(
+            Self::verify(
+                self,
+                _signature,
+                room.advisor.clone(),
+                "end_room",
+                _room_id,
+                _nonce
+            ) == true,
+            "There was an error verifying advisor's signature"
+        );
+
+        let fee_basis_points = self.fee_basis_points;
+        let split = settle_claim(self, &NearClock, _room_id, fee_basis_points)
+            .unwrap_or_else(|e| env::panic_str(e.message()));
+
+        self.collected_fees += split.fee_amount;
+
+        let update_apr_promise = ext_stake_contract::ext(self.token_address.clone())
             .with_static_gas(FT_TRANSFER_GAS)
             .update_apr(env::signer_account_id(), _learner_vote);
 
-        ext_ft_contract::ext(self.token_address.clone())
+        let advisor_transfer_promise = ext_ft_contract::ext(self.token_address.clone())
             .with_static_gas(FT_TRANSFER_GAS)
-            .ft_transfer(
-                room.advisor.clone(),
-                U128::from(room.pending_amount * 95 / 100),
-                None,
-            );
+            .ft_transfer(room.advisor.clone(), U128::from(split.advisor_amount), None);
 
-        room.claimed = true;
-        self.room_list.insert(&_room_id, &room);
+        let refund_transfer_promise = ext_ft_contract::ext(self.token_address.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_transfer(room.learner.clone(), U128::from(split.refund_amount), None);
+
+        update_apr_promise
+            .and(advisor_transfer_promise)
+            .and(refund_transfer_promise)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(5 * TGAS))
+                    .resolve_claim_callback(U128::from(_room_id), U128::from(split.fee_amount)),
+            );
     }
 
     // advisor leave meeting at least 10 minutes then leaner can revert their tokens
     // fe check time advisor leave. If time > 10 minutes, fe will allow learner do this function and create a signature for this fn
     // admin sign
     #[payable]
-    pub fn revert_token(&mut self, _room_id: U128, _signature: Vec<u8>, _signer: Vec<u8>) {
+    pub fn revert_token(
+        &mut self,
+        _room_id: U128,
+        _signature: Vec<u8>,
+        _nonce: u64,
+    ) {
         assert_one_yocto();
+        self.assert_not_paused();
         let _room_id = u128::from(_room_id);
-        // require!(
-        //     Self::verify(&self, _signature, _signer, self.owner.clone()) == true,
-        //     "There was an error verifying admin's signature"
-        // );
+        require!(
+            Self::verify(
+                self,
+                _signature,
+                self.owner.clone(),
+                "revert_token",
+                _room_id,
+                _nonce
+            ) == true,
+            "There was an error verifying admin's signature"
+        );
 
         require!(
-            self.room_list.contains_key(&_room_id) == true,
+            self.contains_room(_room_id) == true,
             "App: Room not existed!"
         );
-        let mut room = self.room_list.get(&_room_id).unwrap();
+        let room = self.get_room(_room_id).unwrap();
+
+        let fee_basis_points = self.fee_basis_points;
+        let split = settle_revert(self, &NearClock, _room_id, fee_basis_points)
+            .unwrap_or_else(|e| env::panic_str(e.message()));
+
+        self.collected_fees += split.fee_amount;
+
+        ext_ft_contract::ext(self.token_address.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_transfer(room.learner.clone(), U128::from(split.learner_amount), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(5 * TGAS))
+                    .resolve_revert_callback(U128::from(_room_id), U128::from(split.fee_amount)),
+            );
+    }
 
+    #[private]
+    pub fn resolve_claim_callback(
+        &mut self,
+        room_id: U128,
+        fee_amount: U128,
+        #[callback_result] update_apr_result: Result<(), PromiseError>,
+        #[callback_result] advisor_transfer_result: Result<(), PromiseError>,
+        #[callback_result] refund_transfer_result: Result<(), PromiseError>,
+    ) {
+        // `update_apr` is independent APR bookkeeping on the staking
+        // contract, not a settlement transfer: its failure alone must not
+        // roll `claimed` back, or the advisor/learner transfers that already
+        // landed would be paid out again on a retried `end_room`.
+        if update_apr_result.is_err() {
+            env::log_str("App: update_apr failed for this room's end_room call");
+        }
+
+        // update_apr.and(advisor_transfer).and(refund_transfer) resolves
+        // each leg's receipt independently, so one ft_transfer can succeed
+        // while the other fails (e.g. the learner isn't storage-registered
+        // on the token while the advisor is). `resolve_claim_payout` tracks
+        // exactly which leg(s) landed so `retry_advisor_payout`/
+        // `retry_refund_payout` only resend the one that failed, instead of
+        // `end_room` recomputing the split and double-paying the leg that
+        // already succeeded.
+        let room_id = u128::from(room_id);
+        let outcome = resolve_claim_payout(
+            self,
+            room_id,
+            advisor_transfer_result.is_ok(),
+            refund_transfer_result.is_ok(),
+        );
+        if let Ok(ClaimPayoutOutcome::FullyUnwound) = outcome {
+            self.collected_fees -= u128::from(fee_amount);
+        }
+    }
+
+    // Resends just the advisor leg of a claimed room's payout after it
+    // failed in `resolve_claim_callback`, without recomputing the split.
+    #[payable]
+    pub fn retry_advisor_payout(&mut self, _room_id: U128) {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let _room_id = u128::from(_room_id);
         require!(
-            Self::now().sub(room.start_time) < room.minutes_last,
-            "App: Room already ended!"
+            self.contains_room(_room_id) == true,
+            "App: Room not existed!"
         );
+        let room = self.get_room(_room_id).unwrap();
+        require!(room.claimed, "App: Room not claimed yet!");
+        require!(!room.advisor_paid, "App: Advisor already paid!");
 
         ext_ft_contract::ext(self.token_address.clone())
             .with_static_gas(FT_TRANSFER_GAS)
-            .ft_transfer(
-                room.learner.clone(),
-                U128::from(room.amount_per_minute.mul(room.minutes_last as u128) * 95 / 100),
-                None,
+            .ft_transfer(room.advisor.clone(), U128::from(room.advisor_amount), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(5 * TGAS))
+                    .resolve_advisor_payout_callback(U128::from(_room_id)),
             );
+    }
 
-        room.reverted = true;
-        self.room_list.insert(&_room_id, &room);
+    #[private]
+    pub fn resolve_advisor_payout_callback(
+        &mut self,
+        room_id: U128,
+        #[callback_result] advisor_transfer_result: Result<(), PromiseError>,
+    ) {
+        if advisor_transfer_result.is_ok() {
+            let room_id = u128::from(room_id);
+            if let Some(mut room) = self.room_list.get(&room_id) {
+                room.advisor_paid = true;
+                self.room_list.insert(&room_id, &room);
+            }
+        }
+    }
+
+    // Resends just the learner refund leg of a claimed room's payout after
+    // it failed in `resolve_claim_callback`, without recomputing the split.
+    #[payable]
+    pub fn retry_refund_payout(&mut self, _room_id: U128) {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let _room_id = u128::from(_room_id);
+        require!(
+            self.contains_room(_room_id) == true,
+            "App: Room not existed!"
+        );
+        let room = self.get_room(_room_id).unwrap();
+        require!(room.claimed, "App: Room not claimed yet!");
+        require!(!room.refund_paid, "App: Learner already refunded!");
+
+        ext_ft_contract::ext(self.token_address.clone())
+            .with_static_gas(FT_TRANSFER_GAS)
+            .ft_transfer(room.learner.clone(), U128::from(room.refund_amount), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(5 * TGAS))
+                    .resolve_refund_payout_callback(U128::from(_room_id)),
+            );
+    }
+
+    #[private]
+    pub fn resolve_refund_payout_callback(
+        &mut self,
+        room_id: U128,
+        #[callback_result] refund_transfer_result: Result<(), PromiseError>,
+    ) {
+        if refund_transfer_result.is_ok() {
+            let room_id = u128::from(room_id);
+            if let Some(mut room) = self.room_list.get(&room_id) {
+                room.refund_paid = true;
+                self.room_list.insert(&room_id, &room);
+            }
+        }
+    }
+
+    #[private]
+    pub fn resolve_revert_callback(
+        &mut self,
+        room_id: U128,
+        fee_amount: U128,
+        #[callback_result] ft_transfer_result: Result<(), PromiseError>,
+    ) {
+        if ft_transfer_result.is_err() {
+            let room_id = u128::from(room_id);
+            if let Some(mut room) = self.room_list.get(&room_id) {
+                room.reverted = false;
+                self.room_list.insert(&room_id, &room);
+            }
+            self.collected_fees -= u128::from(fee_amount);
+        }
     }
 
     #[private]
@@ -264,38 +558,65 @@ impl Contract {
         amount
     }
 
+    // Builds the exact byte message an off-chain signer must sign: the Borsh
+    // encoding of (method_name, room_id, account_id, nonce, current_account_id)
+    // concatenated in order, binding the signature to this contract, this room,
+    // this method and a specific nonce so it can't be replayed elsewhere.
+    fn signed_message(
+        method_name: &str,
+        room_id: u128,
+        account_id: &AccountId,
+        nonce: u64,
+    ) -> Vec<u8> {
+        let mut message = method_name.try_to_vec().unwrap();
+        message.extend(room_id.try_to_vec().unwrap());
+        message.extend(account_id.try_to_vec().unwrap());
+        message.extend(nonce.try_to_vec().unwrap());
+        message.extend(env::current_account_id().try_to_vec().unwrap());
+        message
+    }
+
     #[private]
     pub fn verify(
-        &self,
+        &mut self,
         _signature: Vec<u8>,
-        _signer_public_key: Vec<u8>,
         _account_id: AccountId,
+        _method_name: &str,
+        _room_id: u128,
+        _nonce: u64,
     ) -> bool {
         // https://stackoverflow.com/questions/70041130/how-to-verify-secp256k1-signed-message-in-smart-contract
         // verify signature of app creator
+        //
+        // The public key always comes from the key `_account_id` registered
+        // via `register_public_key`, never from caller-supplied bytes — a
+        // caller no longer has any key argument to pass, so there's nothing
+        // here for an integrator to mistake as load-bearing.
+        let registered_key = self
+            .public_key_list
+            .get(&_account_id)
+            .expect("App: no public key registered for this account");
+
         let signature = ed25519_dalek::Signature::try_from(_signature.as_ref())
             .expect("Signature should be a valid array of 64 bytes [13, 254, 123, ...]");
         let public_key = ed25519_dalek::PublicKey::from_bytes(
-            &bs58::decode(
-                // public key "H5ANpdUoXVwhYBgAgEi1ieMQZKJbwxjPJtHX4vkVcSnF",
-                _signer_public_key,
-            )
-            .into_vec()
-            .unwrap(),
+            &bs58::decode(registered_key).into_vec().unwrap(),
         )
         .unwrap();
-        if let Ok(_) = public_key.verify(_account_id.as_bytes(), &signature) {
-            return true;
-        } else {
+
+        let message = Self::signed_message(_method_name, _room_id, &_account_id, _nonce);
+        if public_key.verify(&message, &signature).is_err() {
             return false;
         }
+
+        let expected_nonce = self.nonce_list.get(&_account_id).unwrap_or(0) + 1;
+        require!(_nonce == expected_nonce, "App: invalid or replayed nonce");
+        self.nonce_list.insert(&_account_id, &_nonce);
+        true
     }
 
     #[private]
     pub fn now() -> i64 {
-        return SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+        NearClock.now()
     }
 }
@@ -0,0 +1,288 @@
+use near_sdk::Balance;
+
+use crate::clock::Clock;
+use crate::store::RoomStore;
+
+/// Why a settlement call was rejected, independent of how the caller wants
+/// to surface it (a `require!` panic on-chain, an assertion in a test).
+pub enum SettlementError {
+    RoomNotFound,
+    AlreadyClaimed,
+    AlreadyReverted,
+    RoomAlreadyEnded,
+}
+
+impl SettlementError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            SettlementError::RoomNotFound => "App: Room not existed!",
+            SettlementError::AlreadyClaimed => "App: Already claimed!",
+            SettlementError::AlreadyReverted => "App: Already reverted!",
+            SettlementError::RoomAlreadyEnded => "App: Room already ended!",
+        }
+    }
+}
+
+pub struct ClaimSplit {
+    pub advisor_amount: Balance,
+    pub refund_amount: Balance,
+    pub fee_amount: Balance,
+}
+
+pub struct RevertSplit {
+    pub learner_amount: Balance,
+    pub fee_amount: Balance,
+}
+
+/// Pro-rata claim accounting for `end_room`: marks the room claimed and
+/// splits `minutes_last * amount_per_minute` between the advisor (for
+/// elapsed minutes, minus the platform fee) and the learner (refund for the
+/// unused remainder). Relies on `amount_per_minute` staying constant for the
+/// room's whole lifetime — `extend_room` rejects a differing rate so this
+/// still accounts for every token actually deposited.
+pub fn settle_claim<S: RoomStore, C: Clock>(
+    store: &mut S,
+    clock: &C,
+    room_id: u128,
+    fee_basis_points: u16,
+) -> Result<ClaimSplit, SettlementError> {
+    let mut room = store.get_room(room_id).ok_or(SettlementError::RoomNotFound)?;
+    if room.claimed {
+        return Err(SettlementError::AlreadyClaimed);
+    }
+    if room.reverted {
+        return Err(SettlementError::AlreadyReverted);
+    }
+
+    let elapsed_minutes = (clock.now() - room.start_time) / 60;
+    let paid_minutes = elapsed_minutes.clamp(0, room.minutes_last);
+    let refund_minutes = room.minutes_last - paid_minutes;
+
+    let advisor_gross = (paid_minutes as u128) * room.amount_per_minute;
+    let advisor_amount = advisor_gross * (10_000 - fee_basis_points as u128) / 10_000;
+    let fee_amount = advisor_gross - advisor_amount;
+    let refund_amount = (refund_minutes as u128) * room.amount_per_minute;
+
+    room.claimed = true;
+    room.advisor_amount = advisor_amount;
+    room.refund_amount = refund_amount;
+    room.advisor_paid = false;
+    room.refund_paid = false;
+    store.insert_room(room_id, room);
+
+    Ok(ClaimSplit {
+        advisor_amount,
+        refund_amount,
+        fee_amount,
+    })
+}
+
+/// Outcome of reconciling a claimed room's two payout legs once their
+/// `ft_transfer` promises resolve independently.
+pub enum ClaimPayoutOutcome {
+    /// Neither leg landed, so nothing left the contract: the claim was
+    /// fully unwound and `end_room` can be retried from scratch.
+    FullyUnwound,
+    /// At least one leg landed: `advisor_paid`/`refund_paid` were updated so
+    /// a retry only resends whichever leg is still missing.
+    Recorded,
+}
+
+/// Called from `resolve_claim_callback` once the advisor/refund transfer
+/// promises resolve. A room's two payout legs settle independently, so one
+/// `ft_transfer` can succeed while the other fails; this tracks exactly
+/// which leg(s) landed instead of collapsing both results into `claimed`,
+/// which would let a retried `end_room` double-pay the leg that already
+/// succeeded.
+pub fn resolve_claim_payout<S: RoomStore>(
+    store: &mut S,
+    room_id: u128,
+    advisor_transfer_ok: bool,
+    refund_transfer_ok: bool,
+) -> Result<ClaimPayoutOutcome, SettlementError> {
+    let mut room = store.get_room(room_id).ok_or(SettlementError::RoomNotFound)?;
+
+    if !advisor_transfer_ok && !refund_transfer_ok {
+        room.claimed = false;
+        store.insert_room(room_id, room);
+        return Ok(ClaimPayoutOutcome::FullyUnwound);
+    }
+
+    room.advisor_paid = room.advisor_paid || advisor_transfer_ok;
+    room.refund_paid = room.refund_paid || refund_transfer_ok;
+    store.insert_room(room_id, room);
+    Ok(ClaimPayoutOutcome::Recorded)
+}
+
+/// Revert accounting for `revert_token`: only valid while the meeting is
+/// still running, pays the learner back the full pending amount minus the
+/// platform fee.
+pub fn settle_revert<S: RoomStore, C: Clock>(
+    store: &mut S,
+    clock: &C,
+    room_id: u128,
+    fee_basis_points: u16,
+) -> Result<RevertSplit, SettlementError> {
+    let mut room = store.get_room(room_id).ok_or(SettlementError::RoomNotFound)?;
+    if room.claimed {
+        return Err(SettlementError::AlreadyClaimed);
+    }
+    if room.reverted {
+        return Err(SettlementError::AlreadyReverted);
+    }
+    if (clock.now() - room.start_time) / 60 >= room.minutes_last {
+        return Err(SettlementError::RoomAlreadyEnded);
+    }
+
+    let gross_amount = room.amount_per_minute * (room.minutes_last as u128);
+    let learner_amount = gross_amount * (10_000 - fee_basis_points as u128) / 10_000;
+    let fee_amount = gross_amount - learner_amount;
+
+    room.reverted = true;
+    store.insert_room(room_id, room);
+
+    Ok(RevertSplit {
+        learner_amount,
+        fee_amount,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::store::MockRoomStore;
+    use crate::Room;
+    use near_sdk::AccountId;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    fn room(start_time: i64, minutes_last: i64, amount_per_minute: Balance) -> Room {
+        Room {
+            advisor: AccountId::from_str("advisor.near").unwrap(),
+            learner: AccountId::from_str("learner.near").unwrap(),
+            start_time,
+            amount_per_minute,
+            minutes_last,
+            pending_amount: amount_per_minute * minutes_last as u128,
+            claimed: false,
+            reverted: false,
+            advisor_amount: 0,
+            refund_amount: 0,
+            advisor_paid: false,
+            refund_paid: false,
+        }
+    }
+
+    fn store_with(room_id: u128, r: Room) -> MockRoomStore {
+        let mut rooms = HashMap::new();
+        rooms.insert(room_id, r);
+        MockRoomStore {
+            rooms,
+            verified_amount: 0,
+        }
+    }
+
+    #[test]
+    fn claim_pays_pro_rata_for_elapsed_minutes() {
+        let mut store = store_with(1, room(0, 10, 100));
+        let clock = FixedClock(5 * 60);
+
+        let split = settle_claim(&mut store, &clock, 1, 500).unwrap();
+
+        assert_eq!(split.advisor_amount, 475); // 5 * 100 * 95%
+        assert_eq!(split.fee_amount, 25);
+        assert_eq!(split.refund_amount, 500); // remaining 5 minutes
+        assert!(store.get_room(1).unwrap().claimed);
+    }
+
+    #[test]
+    fn claim_clamps_elapsed_minutes_to_meeting_length() {
+        let mut store = store_with(1, room(0, 10, 100));
+        let clock = FixedClock(60 * 60); // way past minutes_last
+
+        let split = settle_claim(&mut store, &clock, 1, 500).unwrap();
+
+        assert_eq!(split.advisor_amount, 950); // 10 * 100 * 95%
+        assert_eq!(split.refund_amount, 0);
+    }
+
+    #[test]
+    fn claim_rejects_already_claimed_room() {
+        let mut r = room(0, 10, 100);
+        r.claimed = true;
+        let mut store = store_with(1, r);
+        let clock = FixedClock(60);
+
+        assert!(matches!(
+            settle_claim(&mut store, &clock, 1, 500),
+            Err(SettlementError::AlreadyClaimed)
+        ));
+    }
+
+    #[test]
+    fn claim_payout_records_the_leg_that_landed_on_partial_failure() {
+        let mut store = store_with(1, room(0, 10, 100));
+        let clock = FixedClock(5 * 60);
+        settle_claim(&mut store, &clock, 1, 500).unwrap();
+
+        // Advisor's ft_transfer landed, learner's refund failed.
+        let outcome = resolve_claim_payout(&mut store, 1, true, false).unwrap();
+
+        assert!(matches!(outcome, ClaimPayoutOutcome::Recorded));
+        let room = store.get_room(1).unwrap();
+        assert!(room.claimed); // claim stays committed, not unwound
+        assert!(room.advisor_paid);
+        assert!(!room.refund_paid);
+    }
+
+    #[test]
+    fn claim_payout_fully_unwinds_when_both_legs_fail() {
+        let mut store = store_with(1, room(0, 10, 100));
+        let clock = FixedClock(5 * 60);
+        settle_claim(&mut store, &clock, 1, 500).unwrap();
+
+        let outcome = resolve_claim_payout(&mut store, 1, false, false).unwrap();
+
+        assert!(matches!(outcome, ClaimPayoutOutcome::FullyUnwound));
+        let room = store.get_room(1).unwrap();
+        assert!(!room.claimed);
+        assert!(!room.advisor_paid);
+        assert!(!room.refund_paid);
+    }
+
+    #[test]
+    fn revert_pays_back_minus_fee_before_room_ends() {
+        let mut store = store_with(1, room(0, 10, 100));
+        let clock = FixedClock(5 * 60);
+
+        let split = settle_revert(&mut store, &clock, 1, 500).unwrap();
+
+        assert_eq!(split.learner_amount, 950); // 10 * 100 * 95%
+        assert!(store.get_room(1).unwrap().reverted);
+    }
+
+    #[test]
+    fn revert_rejects_room_already_claimed() {
+        let mut store = store_with(1, room(0, 10, 100));
+        let clock = FixedClock(5 * 60);
+
+        settle_claim(&mut store, &clock, 1, 500).unwrap();
+
+        assert!(matches!(
+            settle_revert(&mut store, &clock, 1, 500),
+            Err(SettlementError::AlreadyClaimed)
+        ));
+    }
+
+    #[test]
+    fn revert_rejects_room_that_already_ended() {
+        let mut store = store_with(1, room(0, 10, 100));
+        let clock = FixedClock(10 * 60);
+
+        assert!(matches!(
+            settle_revert(&mut store, &clock, 1, 500),
+            Err(SettlementError::RoomAlreadyEnded)
+        ));
+    }
+}
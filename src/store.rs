@@ -0,0 +1,56 @@
+use near_sdk::Balance;
+
+use crate::{Contract, Room};
+
+/// Room persistence and the advisor stake threshold, abstracted so the
+/// room-lifecycle rules in `settlement.rs` can run against an in-memory
+/// store in native unit tests instead of requiring a full NEAR VM.
+pub trait RoomStore {
+    fn get_room(&self, room_id: u128) -> Option<Room>;
+    fn insert_room(&mut self, room_id: u128, room: Room);
+    fn contains_room(&self, room_id: u128) -> bool;
+    fn verified_amount(&self) -> Balance;
+}
+
+impl RoomStore for Contract {
+    fn get_room(&self, room_id: u128) -> Option<Room> {
+        self.room_list.get(&room_id)
+    }
+
+    fn insert_room(&mut self, room_id: u128, room: Room) {
+        self.room_list.insert(&room_id, &room);
+    }
+
+    fn contains_room(&self, room_id: u128) -> bool {
+        self.room_list.contains_key(&room_id)
+    }
+
+    fn verified_amount(&self) -> Balance {
+        self.verified_amount
+    }
+}
+
+#[cfg(test)]
+pub struct MockRoomStore {
+    pub rooms: std::collections::HashMap<u128, Room>,
+    pub verified_amount: Balance,
+}
+
+#[cfg(test)]
+impl RoomStore for MockRoomStore {
+    fn get_room(&self, room_id: u128) -> Option<Room> {
+        self.rooms.get(&room_id).cloned()
+    }
+
+    fn insert_room(&mut self, room_id: u128, room: Room) {
+        self.rooms.insert(room_id, room);
+    }
+
+    fn contains_room(&self, room_id: u128) -> bool {
+        self.rooms.contains_key(&room_id)
+    }
+
+    fn verified_amount(&self) -> Balance {
+        self.verified_amount
+    }
+}